@@ -0,0 +1,908 @@
+use std::{collections::HashMap, fmt};
+use walrus::{
+    ConstExpr, ConstOp, ElementItems, ExportItem, FunctionBuilder, FunctionId, FunctionKind,
+    GlobalId, InstrSeqBuilder, LocalFunction, LocalId, Module, ModuleConfig, ModuleGlobals,
+    ModuleLocals, ModuleTables, ModuleTypes, TableId, TypeId, ValType,
+    ir::{self, InstrSeq, InstrSeqId},
+};
+
+/// Configures how [`weave`] threads a context value through a module.
+pub struct ContextConfig {
+    /// Name of the import that reads the current context (default `__ctx_get`).
+    pub ctx_get_import: String,
+    /// Name of the import that replaces the current context (default `__ctx_set`).
+    pub ctx_set_import: String,
+    /// What to do when one or both of the ctx imports aren't present in the module.
+    pub on_missing_ctx_import: OnMissingCtxImport,
+    /// The value types making up the context, in order (default a single `i64`).
+    /// A multi-word shape (e.g. `[i32, i32]` for a fat pointer) is threaded as
+    /// that many trailing params/locals, and `__ctx_set` is expected to leave
+    /// that many values on the stack.
+    pub ctx_shape: Vec<ValType>,
+    /// How each generated export shim obtains the context it hands to the
+    /// rewritten function (default [`InitialContext::Zero`]). Only consulted
+    /// when `strategy` is [`ContextStrategy::Parameter`].
+    pub initial_context: InitialContext,
+    /// How the context actually gets from a ctx-set to a later ctx-get
+    /// (default [`ContextStrategy::Parameter`]).
+    pub strategy: ContextStrategy,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            ctx_get_import: String::from("__ctx_get"),
+            ctx_set_import: String::from("__ctx_set"),
+            on_missing_ctx_import: OnMissingCtxImport::Error,
+            ctx_shape: vec![ValType::I64],
+            initial_context: InitialContext::Zero,
+            strategy: ContextStrategy::Parameter,
+        }
+    }
+}
+
+/// Selects how the context value travels between a `__ctx_set` and the next
+/// `__ctx_get`.
+pub enum ContextStrategy {
+    /// Append `ctx_shape` as trailing params to every function and call site,
+    /// and thread it through locals. This is the original, reentrancy-safe
+    /// behavior: every call frame carries its own copy of the context on the
+    /// stack, so nested/recursive calls never clobber each other's context.
+    /// The cost is inflated signatures and call sites, and the
+    /// tables/indirect-call rewriting in [`weave`].
+    Parameter,
+    /// Store the context in these pre-existing mutable globals (one per word
+    /// of `ctx_shape`, in order) instead of threading it as a parameter.
+    /// `__ctx_get` lowers to `global.get`, `__ctx_set` to `global.set`,
+    /// and calls pass no extra argument, so function signatures, exports and
+    /// the table are left completely untouched.
+    ///
+    /// This is **not reentrancy-safe**: the context lives in one shared
+    /// location, so if a woven function calls back into the module (directly,
+    /// through the table, or via a host callback) while another call is still
+    /// "in" a context, the inner call's `__ctx_set` overwrites it for the
+    /// outer call too. Only use this mode when the module's calls don't
+    /// nest, or when that's acceptable. In exchange it avoids the call-site
+    /// and signature bloat of [`ContextStrategy::Parameter`] entirely, and
+    /// `initial_context` is ignored since there's no export-call boundary to
+    /// seed.
+    ///
+    /// The `GlobalId`s refer to the module passed into [`weave`], whose
+    /// globals are carried over unchanged, the same way
+    /// [`InitialContext::Global`] references them.
+    Global(Vec<GlobalId>),
+}
+
+/// Selects where an export shim's initial context comes from.
+pub enum InitialContext {
+    /// Seed with a zero value of each word in `ctx_shape` (the original,
+    /// always-start-from-zero behavior).
+    Zero,
+    /// Append `ctx_shape` as extra trailing parameters of the *exported*
+    /// signature, so the host passes the root context in directly.
+    ExportParam,
+    /// Call a zero-argument import (matched by name, the same way as
+    /// `ctx_get_import`/`ctx_set_import`) whose results are `ctx_shape`,
+    /// and use its return values as the initial context.
+    Import(String),
+    /// Read the initial context from these globals, one per word of
+    /// `ctx_shape`, in order. The `GlobalId`s refer to the module passed
+    /// into [`weave`], whose globals are carried over unchanged.
+    Global(Vec<GlobalId>),
+}
+
+/// Behavior selected by [`ContextConfig::on_missing_ctx_import`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnMissingCtxImport {
+    /// Fail the weave with [`WeaveError::MissingCtxImport`].
+    Error,
+    /// Proceed without context threading calls; the module is still rewritten
+    /// to carry the context parameter, but no instruction is treated as a
+    /// ctx-get/ctx-set.
+    NoOp,
+}
+
+#[derive(Debug)]
+pub enum WeaveError {
+    /// Only one of `ctx_get_import`/`ctx_set_import` was found in the module.
+    PartialCtxImport,
+    /// Neither ctx import was found and [`OnMissingCtxImport::Error`] was selected.
+    MissingCtxImport,
+    /// [`InitialContext::Import`] named an import that the module doesn't have.
+    MissingInitialContextImport,
+    /// [`ContextStrategy::Global`] or [`InitialContext::Global`] supplied a
+    /// different number of globals than `ctx_shape` has words, a global
+    /// whose `ValType` doesn't match its `ctx_shape` word, or (for
+    /// [`ContextStrategy::Global`] only) an immutable global.
+    CtxGlobalsShapeMismatch,
+    /// `ctx_shape` contains a `ValType` that can't be constructed as a
+    /// constant (only `i32`/`i64`/`f32`/`f64` are supported).
+    UnsupportedCtxShape,
+}
+
+impl fmt::Display for WeaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WeaveError::PartialCtxImport => {
+                write!(f, "module imports only one of ctx_get/ctx_set")
+            }
+            WeaveError::MissingCtxImport => write!(f, "module imports neither ctx_get nor ctx_set"),
+            WeaveError::MissingInitialContextImport => {
+                write!(f, "module doesn't import the configured initial-context function")
+            }
+            WeaveError::CtxGlobalsShapeMismatch => {
+                write!(f, "globals don't match ctx_shape in count, type, or mutability")
+            }
+            WeaveError::UnsupportedCtxShape => {
+                write!(f, "ctx_shape contains a type other than i32/i64/f32/f64")
+            }
+        }
+    }
+}
+
+impl std::error::Error for WeaveError {}
+
+pub type Result<T> = std::result::Result<T, WeaveError>;
+
+fn find_block(stack: &[(InstrSeqId, InstrSeqId)], id: InstrSeqId) -> Option<InstrSeqId> {
+    stack.iter().find(|(k, _)| *k == id).map(|(_, v)| *v)
+}
+
+struct Maps {
+    funcs: HashMap<FunctionId, FunctionId>,
+    locals: HashMap<LocalId, LocalId>,
+    ctx_set: Option<FunctionId>,
+    ctx_get: Option<FunctionId>,
+}
+
+// Appends the context param to an indirect-call signature, caching by the
+// original `TypeId` so repeated call sites against the same type share one
+// new type instead of growing the type section every time.
+fn ctx_call_type(
+    old_types: &ModuleTypes,
+    new_types: &mut ModuleTypes,
+    cache: &mut HashMap<TypeId, TypeId>,
+    old_ty: TypeId,
+    ctx_shape: &[ValType],
+) -> TypeId {
+    *cache.entry(old_ty).or_insert_with(|| {
+        let ty = old_types.get(old_ty);
+        let mut params = ty.params().to_vec();
+        params.extend_from_slice(ctx_shape);
+        new_types.add(&params, ty.results())
+    })
+}
+
+// call_indirect/return_call_indirect need to spill the table index below the
+// ctx args pushed in front of it; table64 tables index with i64 instead of
+// the usual i32, so the scratch local has to match.
+fn table_index_ty(old_tables: &ModuleTables, table: TableId) -> ValType {
+    if old_tables.get(table).table64 {
+        ValType::I64
+    } else {
+        ValType::I32
+    }
+}
+
+// Pushes a zero value of `ty` onto the stack, used to seed the initial
+// context at export boundaries. Only the ValTypes accepted by
+// `validate_ctx_shape` ever reach here.
+fn zero_const(body: &mut InstrSeqBuilder, ty: ValType) {
+    match ty {
+        ValType::I32 => {
+            body.i32_const(0);
+        }
+        ValType::I64 => {
+            body.i64_const(0);
+        }
+        ValType::F32 => {
+            body.f32_const(0.0);
+        }
+        ValType::F64 => {
+            body.f64_const(0.0);
+        }
+        ValType::V128 | ValType::Ref(_) => {
+            unreachable!("ctx shape must be i32/i64/f32/f64")
+        }
+    }
+}
+
+// InstrSeqBuilder has no v128_const or ref-const builder method, so
+// zero_const (and therefore InitialContext::Zero) is restricted to the four
+// scalar numeric types it does support. This has nothing to do with the
+// other InitialContext/ContextStrategy variants, which never call
+// zero_const, so callers must only apply this where Zero is actually
+// reachable (i.e. ContextStrategy::Parameter + InitialContext::Zero).
+fn validate_ctx_shape(ctx_shape: &[ValType]) -> Result<()> {
+    if ctx_shape
+        .iter()
+        .all(|ty| matches!(ty, ValType::I32 | ValType::I64 | ValType::F32 | ValType::F64))
+    {
+        Ok(())
+    } else {
+        Err(WeaveError::UnsupportedCtxShape)
+    }
+}
+
+// Shared by ContextStrategy::Global and InitialContext::Global: both take a
+// caller-supplied Vec<GlobalId> that's meant to carry exactly ctx_shape, in
+// matching ValTypes. ContextStrategy::Global additionally writes through
+// these globals via __ctx_set, so they must also be mutable there.
+fn validate_globals_shape(
+    module_globals: &ModuleGlobals,
+    globals: &[GlobalId],
+    ctx_shape: &[ValType],
+    require_mutable: bool,
+) -> Result<()> {
+    if globals.len() != ctx_shape.len() {
+        return Err(WeaveError::CtxGlobalsShapeMismatch);
+    }
+    for (&id, &ty) in globals.iter().zip(ctx_shape) {
+        let global = module_globals.get(id);
+        if global.ty != ty || (require_mutable && !global.mutable) {
+            return Err(WeaveError::CtxGlobalsShapeMismatch);
+        }
+    }
+    Ok(())
+}
+
+// Remaps the FunctionId(s) a const-expr's RefFunc (or, inside Extended, a
+// nested ConstOp::RefFunc) points at; every other leaf/op carries no
+// FunctionId and passes through unchanged.
+fn remap_const_expr(expr: &ConstExpr, funcs: &HashMap<FunctionId, FunctionId>) -> ConstExpr {
+    match expr {
+        ConstExpr::RefFunc(func) => ConstExpr::RefFunc(*funcs.get(func).unwrap()),
+        ConstExpr::Extended(ops) => ConstExpr::Extended(
+            ops.iter()
+                .map(|op| match op {
+                    ConstOp::RefFunc(func) => ConstOp::RefFunc(*funcs.get(func).unwrap()),
+                    other => *other,
+                })
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+// Bundles the state that's invariant across one weave() call's recursive
+// copy_seq descent (as opposed to func/ctx/stack/seq/builder, which change
+// at every nested block/loop/if), so a future cross-cutting concern can be
+// threaded through without growing copy_seq's own parameter list again.
+struct RewriteCtx<'a> {
+    maps: &'a Maps,
+    locals: &'a mut ModuleLocals,
+    old_types: &'a ModuleTypes,
+    new_types: &'a mut ModuleTypes,
+    indirect_types: &'a mut HashMap<TypeId, TypeId>,
+    old_tables: &'a ModuleTables,
+    ctx_shape: &'a [ValType],
+    ctx_globals: &'a [GlobalId],
+}
+
+fn copy_seq(
+    func: &LocalFunction,
+    mut ctx: Vec<LocalId>,
+    stack: &mut Vec<(InstrSeqId, InstrSeqId)>,
+    seq: &InstrSeq,
+    builder: &mut InstrSeqBuilder,
+    rw: &mut RewriteCtx,
+) -> InstrSeqId {
+    stack.push((seq.id(), builder.id()));
+    for (instr, _) in &seq.instrs {
+        if instr.is_br() {
+            let i = instr.unwrap_br();
+            let block = find_block(stack, i.block).unwrap();
+            builder.br(block);
+        } else if instr.is_br_if() {
+            let i = instr.unwrap_br_if();
+            let block = find_block(stack, i.block).unwrap();
+            builder.br_if(block);
+        } else if instr.is_br_table() {
+            let i = instr.unwrap_br_table();
+            let blocks = i
+                .blocks
+                .iter()
+                .map(|block| find_block(stack, *block))
+                .collect::<Option<Box<_>>>()
+                .unwrap();
+            let default = find_block(stack, i.default).unwrap();
+            builder.br_table(blocks, default);
+        // =================================================
+        } else if instr.is_block() {
+            let i = instr.unwrap_block();
+            let block = func.block(i.seq);
+            let mut block_builder = builder.dangling_instr_seq(block.ty);
+            let seq = copy_seq(func, ctx.clone(), stack, block, &mut block_builder, rw);
+            builder.instr(ir::Block { seq });
+        } else if instr.is_loop() {
+            let i = instr.unwrap_loop();
+            let block = func.block(i.seq);
+            let mut block_builder = builder.dangling_instr_seq(block.ty);
+            let seq = copy_seq(func, ctx.clone(), stack, block, &mut block_builder, rw);
+            builder.instr(ir::Loop { seq });
+        } else if instr.is_if_else() {
+            let i = instr.unwrap_if_else();
+            let consequent = func.block(i.consequent);
+            let mut consequent_builder = builder.dangling_instr_seq(consequent.ty);
+            let consequent = copy_seq(
+                func,
+                ctx.clone(),
+                stack,
+                consequent,
+                &mut consequent_builder,
+                rw,
+            );
+            let alternative = func.block(i.alternative);
+            let mut alternative_builder = builder.dangling_instr_seq(alternative.ty);
+            let alternative = copy_seq(
+                func,
+                ctx.clone(),
+                stack,
+                alternative,
+                &mut alternative_builder,
+                rw,
+            );
+            builder.instr(ir::IfElse {
+                consequent,
+                alternative,
+            });
+        // =================================================
+        } else if instr.is_local_get() {
+            let i = instr.unwrap_local_get();
+            let local = rw.maps.locals.get(&i.local).unwrap();
+            builder.local_get(*local);
+        } else if instr.is_local_set() {
+            let i = instr.unwrap_local_set();
+            let local = rw.maps.locals.get(&i.local).unwrap();
+            builder.local_set(*local);
+        } else if instr.is_local_tee() {
+            let i = instr.unwrap_local_tee();
+            let local = rw.maps.locals.get(&i.local).unwrap();
+            builder.local_tee(*local);
+        // =================================================
+        } else if instr.is_ref_func() {
+            let i = instr.unwrap_ref_func();
+            let func = rw.maps.funcs.get(&i.func).unwrap();
+            builder.ref_func(*func);
+        } else if instr.is_call() {
+            let i = instr.unwrap_call();
+            if Some(i.func) == rw.maps.ctx_get {
+                if rw.ctx_globals.is_empty() {
+                    for &word in &ctx {
+                        builder.local_get(word);
+                    }
+                } else {
+                    for &global in rw.ctx_globals {
+                        builder.global_get(global);
+                    }
+                }
+            } else if Some(i.func) == rw.maps.ctx_set {
+                if rw.ctx_globals.is_empty() {
+                    let mut new_ctx = Vec::with_capacity(rw.ctx_shape.len());
+                    for &ty in rw.ctx_shape.iter().rev() {
+                        let word = rw.locals.add(ty);
+                        rw.locals.get_mut(word).name = Some(String::from("ctx"));
+                        builder.local_set(word);
+                        new_ctx.push(word);
+                    }
+                    new_ctx.reverse();
+                    ctx = new_ctx;
+                } else {
+                    for &global in rw.ctx_globals.iter().rev() {
+                        builder.global_set(global);
+                    }
+                }
+            } else {
+                let func = rw.maps.funcs.get(&i.func).unwrap();
+                for &word in &ctx {
+                    builder.local_get(word);
+                }
+                builder.call(*func);
+            }
+        } else if instr.is_return_call() {
+            let i = instr.unwrap_return_call();
+            let func = rw.maps.funcs.get(&i.func).unwrap();
+            for &word in &ctx {
+                builder.local_get(word);
+            }
+            builder.return_call(*func);
+        } else if instr.is_call_indirect() {
+            let i = instr.unwrap_call_indirect();
+            let new_ty = ctx_call_type(
+                rw.old_types,
+                rw.new_types,
+                rw.indirect_types,
+                i.ty,
+                rw.ctx_shape,
+            );
+            let idx = rw.locals.add(table_index_ty(rw.old_tables, i.table));
+            builder.local_set(idx);
+            for &word in &ctx {
+                builder.local_get(word);
+            }
+            builder.local_get(idx);
+            builder.call_indirect(new_ty, i.table);
+        } else if instr.is_return_call_indirect() {
+            let i = instr.unwrap_return_call_indirect();
+            let new_ty = ctx_call_type(
+                rw.old_types,
+                rw.new_types,
+                rw.indirect_types,
+                i.ty,
+                rw.ctx_shape,
+            );
+            let idx = rw.locals.add(table_index_ty(rw.old_tables, i.table));
+            builder.local_set(idx);
+            for &word in &ctx {
+                builder.local_get(word);
+            }
+            builder.local_get(idx);
+            builder.return_call_indirect(new_ty, i.table);
+        // =================================================
+        } else {
+            builder.instr(instr.clone());
+        }
+    }
+    stack.pop().unwrap().1
+}
+
+/// Rewrites `module` so that every function can read/write `config.ctx_shape`
+/// worth of context through the configured ctx-get/ctx-set imports. Under
+/// [`ContextStrategy::Parameter`] (the default) this is done by appending
+/// `ctx_shape` as extra trailing parameters threaded through every call;
+/// under [`ContextStrategy::Global`] it's done through caller-supplied
+/// globals instead, and signatures/calls/the table are left untouched.
+pub fn weave(module: Module, config: &ContextConfig) -> Result<Module> {
+    let mut new_module = Module::with_config(ModuleConfig::new());
+
+    if let (ContextStrategy::Parameter, InitialContext::Global(globals)) =
+        (&config.strategy, &config.initial_context)
+    {
+        validate_globals_shape(&module.globals, globals, &config.ctx_shape, false)?;
+    }
+
+    let ctx_globals: &[GlobalId] = match &config.strategy {
+        ContextStrategy::Parameter => &[],
+        ContextStrategy::Global(globals) => {
+            validate_globals_shape(&module.globals, globals, &config.ctx_shape, true)?;
+            globals
+        }
+    };
+
+    // zero_const (reached only via ContextStrategy::Parameter +
+    // InitialContext::Zero) is the only place ctx_shape's ValTypes actually
+    // need to be constructible as constants, so only validate it there.
+    if matches!(config.strategy, ContextStrategy::Parameter)
+        && matches!(config.initial_context, InitialContext::Zero)
+    {
+        validate_ctx_shape(&config.ctx_shape)?;
+    }
+    // The shape actually appended to signatures/call sites: under the Global
+    // strategy the context never travels as a parameter, so this is empty.
+    let append_shape: &[ValType] = match &config.strategy {
+        ContextStrategy::Parameter => &config.ctx_shape,
+        ContextStrategy::Global(_) => &[],
+    };
+
+    let ctx = append_shape
+        .iter()
+        .map(|&ty| {
+            let word = new_module.locals.add(ty);
+            new_module.locals.get_mut(word).name = Some(String::from("ctx"));
+            word
+        })
+        .collect::<Vec<_>>();
+
+    let locals_map = module
+        .locals
+        .iter()
+        .cloned()
+        .map(|local| {
+            let new_local = new_module.locals.add(local.ty());
+            new_module.locals.get_mut(new_local).name = local.name.clone();
+            (local.id(), new_local)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut ctx_set = None;
+    let mut ctx_get = None;
+    let mut init_ctx_import = None;
+    let mut funcs_map = HashMap::new();
+    for old_func in module.functions() {
+        let old_ty = module.types.get(old_func.ty());
+        let mut new_params = old_ty.params().to_vec();
+        new_params.extend_from_slice(append_shape);
+        let new_results = old_ty.results().to_vec();
+        let mut fb = FunctionBuilder::new(&mut new_module.types, &new_params, &new_results);
+        match &old_func.kind {
+            FunctionKind::Local(func) => {
+                old_func
+                    .name
+                    .as_deref()
+                    .map(|name| fb.name(name.to_string()));
+                let args = func
+                    .args
+                    .iter()
+                    .map(|arg| *locals_map.get(arg).unwrap())
+                    .chain(ctx.iter().copied())
+                    .collect::<Vec<_>>();
+                let new_func = fb.finish(args, &mut new_module.funcs);
+                funcs_map.insert(old_func.id(), new_func);
+            }
+            FunctionKind::Import(i) => {
+                let import = module.imports.get(i.import);
+                if import.name == config.ctx_get_import {
+                    ctx_get = Some(old_func.id());
+                    continue;
+                } else if import.name == config.ctx_set_import {
+                    ctx_set = Some(old_func.id());
+                    continue;
+                } else if matches!(config.strategy, ContextStrategy::Parameter)
+                    && matches!(&config.initial_context, InitialContext::Import(name) if *name == import.name)
+                {
+                    // This import only ever gets called from export shims, before
+                    // any context exists yet, so it's carried over unshimmed
+                    // instead of gaining a ctx param like a regular import.
+                    let new_ty = new_module.types.add(old_ty.params(), old_ty.results());
+                    let new_import =
+                        new_module.add_import_func(&import.module, &import.name, new_ty);
+                    init_ctx_import = Some(new_import.0);
+                    continue;
+                }
+                let new_ty = new_module.types.add(old_ty.params(), old_ty.results());
+                let new_import = new_module.add_import_func(&import.module, &import.name, new_ty);
+
+                fb.name(format!("import_shim::{}", import.name));
+                let mut body = fb.func_body();
+                let args = old_ty
+                    .params()
+                    .iter()
+                    .cloned()
+                    .map(|ty| {
+                        let arg = new_module.locals.add(ty);
+                        body.local_get(arg);
+                        arg
+                    })
+                    .chain(ctx.iter().copied())
+                    .collect::<Vec<_>>();
+                body.call(new_import.0);
+                let import_shim = fb.finish(args, &mut new_module.funcs);
+                funcs_map.insert(old_func.id(), import_shim);
+            }
+            FunctionKind::Uninitialized(_) => unreachable!(),
+        }
+    }
+
+    let (ctx_get, ctx_set) = match (ctx_get, ctx_set) {
+        (Some(g), Some(s)) => (Some(g), Some(s)),
+        (None, None) => match config.on_missing_ctx_import {
+            OnMissingCtxImport::Error => return Err(WeaveError::MissingCtxImport),
+            OnMissingCtxImport::NoOp => (None, None),
+        },
+        _ => return Err(WeaveError::PartialCtxImport),
+    };
+    if matches!(config.strategy, ContextStrategy::Parameter)
+        && matches!(config.initial_context, InitialContext::Import(_))
+        && init_ctx_import.is_none()
+    {
+        return Err(WeaveError::MissingInitialContextImport);
+    }
+
+    let maps = Maps {
+        funcs: funcs_map,
+        locals: locals_map,
+        ctx_get,
+        ctx_set,
+    };
+    let mut indirect_types = HashMap::new();
+    let mut rw = RewriteCtx {
+        maps: &maps,
+        locals: &mut new_module.locals,
+        old_types: &module.types,
+        new_types: &mut new_module.types,
+        indirect_types: &mut indirect_types,
+        old_tables: &module.tables,
+        ctx_shape: append_shape,
+        ctx_globals,
+    };
+    for old_func in module.functions() {
+        if let FunctionKind::Local(func) = &old_func.kind {
+            let new_id = rw.maps.funcs.get(&old_func.id()).unwrap();
+            let mut body = new_module
+                .funcs
+                .get_mut(*new_id)
+                .kind
+                .unwrap_local_mut()
+                .builder_mut()
+                .func_body();
+            let mut stack = Vec::new();
+            copy_seq(
+                func,
+                ctx.clone(),
+                &mut stack,
+                func.block(func.entry_block()),
+                &mut body,
+                &mut rw,
+            );
+        }
+    }
+
+    for e in module.exports.iter() {
+        if let ExportItem::Function(f) = e.item {
+            let ty = module.types.get(module.funcs.get(f).ty());
+            let export_shim = *maps.funcs.get(&f).unwrap();
+            let seed_ctx = matches!(config.strategy, ContextStrategy::Parameter);
+
+            let mut export_params = ty.params().to_vec();
+            if seed_ctx && matches!(config.initial_context, InitialContext::ExportParam) {
+                export_params.extend_from_slice(&config.ctx_shape);
+            }
+            let mut fb = FunctionBuilder::new(&mut new_module.types, &export_params, ty.results());
+            fb.name(format!("export_shim::{}", e.name));
+            let mut body = fb.func_body();
+            let mut args = ty
+                .params()
+                .iter()
+                .cloned()
+                .map(|ty| {
+                    let arg = new_module.locals.add(ty);
+                    body.local_get(arg);
+                    arg
+                })
+                .collect::<Vec<_>>();
+
+            // Under ContextStrategy::Global there's no per-call ctx to seed:
+            // the context already lives in the globals, wherever a previous
+            // ctx-set (or the module's own global initializer) left it.
+            if seed_ctx {
+                match &config.initial_context {
+                    InitialContext::Zero => {
+                        for &ty in &config.ctx_shape {
+                            zero_const(&mut body, ty);
+                        }
+                    }
+                    InitialContext::ExportParam => {
+                        let init_args = config
+                            .ctx_shape
+                            .iter()
+                            .map(|&ty| {
+                                let arg = new_module.locals.add(ty);
+                                body.local_get(arg);
+                                arg
+                            })
+                            .collect::<Vec<_>>();
+                        args.extend(init_args);
+                    }
+                    InitialContext::Import(_) => {
+                        body.call(init_ctx_import.unwrap());
+                    }
+                    InitialContext::Global(globals) => {
+                        for &global in globals {
+                            body.global_get(global);
+                        }
+                    }
+                }
+            }
+
+            body.call(export_shim);
+            let new_export = fb.finish(args, &mut new_module.funcs);
+            new_module.exports.add(&e.name, new_export);
+        } else {
+            new_module.exports.add(&e.name, e.item);
+        }
+    }
+
+    new_module.customs = module.customs;
+    new_module.data = module.data;
+    new_module.debug = module.debug;
+    new_module.globals = module.globals;
+    new_module.memories = module.memories;
+    new_module.producers = module.producers;
+    new_module.start = module.start.map(|func| *maps.funcs.get(&func).unwrap());
+    // Table limits/types don't reference functions, so they can be copied
+    // verbatim; the element segments below are what actually need remapping,
+    // since their FunctionIds point at functions that no longer exist.
+    new_module.tables = module.tables;
+    for elem in module.elements.iter() {
+        let items = match &elem.items {
+            ElementItems::Functions(funcs) => ElementItems::Functions(
+                funcs
+                    .iter()
+                    .map(|func| *maps.funcs.get(func).unwrap())
+                    .collect(),
+            ),
+            ElementItems::Expressions(ty, exprs) => ElementItems::Expressions(
+                *ty,
+                exprs
+                    .iter()
+                    .map(|expr| remap_const_expr(expr, &maps.funcs))
+                    .collect(),
+            ),
+        };
+        new_module.elements.add(elem.kind.clone(), items);
+    }
+
+    Ok(new_module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use walrus::ir::Value;
+
+    // Builds a module importing __ctx_get/__ctx_set typed against `ctx_shape`,
+    // plus one exported local function `run` that round-trips its argument
+    // through ctx_set then ctx_get, so every rewrite path in `weave` (calls,
+    // locals, the export shim) gets exercised.
+    fn basic_module(ctx_shape: &[ValType]) -> Module {
+        let mut module = Module::default();
+
+        let get_ty = module.types.add(&[], ctx_shape);
+        let (ctx_get, _) = module.add_import_func("env", "__ctx_get", get_ty);
+        let set_ty = module.types.add(ctx_shape, &[]);
+        let (ctx_set, _) = module.add_import_func("env", "__ctx_set", set_ty);
+
+        let mut fb = FunctionBuilder::new(&mut module.types, ctx_shape, ctx_shape);
+        let args = ctx_shape
+            .iter()
+            .map(|&ty| module.locals.add(ty))
+            .collect::<Vec<_>>();
+        let mut body = fb.func_body();
+        for &arg in &args {
+            body.local_get(arg);
+        }
+        body.call(ctx_set);
+        body.call(ctx_get);
+        let run = fb.finish(args, &mut module.funcs);
+        module.exports.add("run", run);
+
+        module
+    }
+
+    fn i64_shape() -> Vec<ValType> {
+        vec![ValType::I64]
+    }
+
+    #[test]
+    fn missing_ctx_import_errors_by_default() {
+        let module = Module::default();
+        let config = ContextConfig::default();
+        assert!(matches!(
+            weave(module, &config),
+            Err(WeaveError::MissingCtxImport)
+        ));
+    }
+
+    #[test]
+    fn missing_ctx_import_is_noop_when_configured() {
+        let module = Module::default();
+        let config = ContextConfig {
+            on_missing_ctx_import: OnMissingCtxImport::NoOp,
+            ..ContextConfig::default()
+        };
+        assert!(weave(module, &config).is_ok());
+    }
+
+    #[test]
+    fn partial_ctx_import_errors() {
+        let mut module = Module::default();
+        let get_ty = module.types.add(&[], &i64_shape());
+        module.add_import_func("env", "__ctx_get", get_ty);
+
+        let config = ContextConfig::default();
+        assert!(matches!(
+            weave(module, &config),
+            Err(WeaveError::PartialCtxImport)
+        ));
+    }
+
+    #[test]
+    fn unsupported_ctx_shape_errors() {
+        let module = basic_module(&[ValType::V128]);
+        let config = ContextConfig {
+            ctx_shape: vec![ValType::V128],
+            ..ContextConfig::default()
+        };
+        assert!(matches!(
+            weave(module, &config),
+            Err(WeaveError::UnsupportedCtxShape)
+        ));
+    }
+
+    #[test]
+    fn parameter_strategy_with_zero_initial_context_succeeds() {
+        let module = basic_module(&i64_shape());
+        let config = ContextConfig::default();
+        assert!(weave(module, &config).is_ok());
+    }
+
+    #[test]
+    fn initial_context_export_param_succeeds() {
+        let module = basic_module(&i64_shape());
+        let config = ContextConfig {
+            initial_context: InitialContext::ExportParam,
+            ..ContextConfig::default()
+        };
+        assert!(weave(module, &config).is_ok());
+    }
+
+    #[test]
+    fn initial_context_import_missing_errors() {
+        let module = basic_module(&i64_shape());
+        let config = ContextConfig {
+            initial_context: InitialContext::Import(String::from("seed")),
+            ..ContextConfig::default()
+        };
+        assert!(matches!(
+            weave(module, &config),
+            Err(WeaveError::MissingInitialContextImport)
+        ));
+    }
+
+    #[test]
+    fn initial_context_import_present_succeeds() {
+        let mut module = basic_module(&i64_shape());
+        let seed_ty = module.types.add(&[], &i64_shape());
+        module.add_import_func("env", "seed", seed_ty);
+        let config = ContextConfig {
+            initial_context: InitialContext::Import(String::from("seed")),
+            ..ContextConfig::default()
+        };
+        assert!(weave(module, &config).is_ok());
+    }
+
+    #[test]
+    fn initial_context_global_shape_mismatch_errors() {
+        let module = basic_module(&i64_shape());
+        let config = ContextConfig {
+            initial_context: InitialContext::Global(vec![]),
+            ..ContextConfig::default()
+        };
+        assert!(matches!(
+            weave(module, &config),
+            Err(WeaveError::CtxGlobalsShapeMismatch)
+        ));
+    }
+
+    #[test]
+    fn initial_context_global_succeeds_even_if_immutable() {
+        let mut module = basic_module(&i64_shape());
+        let global = module
+            .globals
+            .add_local(ValType::I64, false, false, ConstExpr::Value(Value::I64(0)));
+        let config = ContextConfig {
+            initial_context: InitialContext::Global(vec![global]),
+            ..ContextConfig::default()
+        };
+        assert!(weave(module, &config).is_ok());
+    }
+
+    #[test]
+    fn context_strategy_global_requires_mutable_globals() {
+        let mut module = basic_module(&i64_shape());
+        let global = module
+            .globals
+            .add_local(ValType::I64, false, false, ConstExpr::Value(Value::I64(0)));
+        let config = ContextConfig {
+            strategy: ContextStrategy::Global(vec![global]),
+            ..ContextConfig::default()
+        };
+        assert!(matches!(
+            weave(module, &config),
+            Err(WeaveError::CtxGlobalsShapeMismatch)
+        ));
+    }
+
+    #[test]
+    fn context_strategy_global_succeeds_with_mutable_global() {
+        let mut module = basic_module(&i64_shape());
+        let global = module
+            .globals
+            .add_local(ValType::I64, true, false, ConstExpr::Value(Value::I64(0)));
+        let config = ContextConfig {
+            strategy: ContextStrategy::Global(vec![global]),
+            ..ContextConfig::default()
+        };
+        assert!(weave(module, &config).is_ok());
+    }
+}